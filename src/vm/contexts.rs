@@ -1,4 +1,6 @@
 use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::cell::RefCell;
 use std::fmt;
 
 use vm::errors::{InterpreterError, UncheckedError, RuntimeErrorType, InterpreterResult as Result};
@@ -14,6 +16,191 @@ use burnchains::BurnchainHeaderHash;
 
 pub const MAX_CONTEXT_DEPTH: u16 = 256;
 
+/// Coarse weight classes used to price built-in operations against the
+/// cost meter. `Environment::charge_expr_tree` charges `COST_EVAL_STEP` per
+/// node of the expression trees it walks, plus, for calls to recognized
+/// built-ins, a weight drawn from the class that best describes their work.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CostFunction {
+    Arithmetic,
+    Hashing,
+    DbRead,
+    DbWrite,
+    ListLength,
+}
+
+impl CostFunction {
+    // Base weight per unit of work for this operation class. `size` is
+    // the class-specific magnitude (e.g., list length, byte length).
+    pub fn weigh(&self, size: u64) -> u64 {
+        let per_unit = match self {
+            CostFunction::Arithmetic => 1,
+            CostFunction::Hashing => 4,
+            CostFunction::DbRead => 10,
+            CostFunction::DbWrite => 20,
+            CostFunction::ListLength => 1,
+        };
+        per_unit.saturating_mul(size.max(1))
+    }
+}
+
+pub const COST_EVAL_STEP: u64 = 1;
+
+// Pure core of GlobalContext::charge_storage's limit check, pulled out so
+//   it can be unit tested without needing a real ContractDatabase to
+//   construct a GlobalContext.
+fn storage_limit_exceeded(total_storage_delta: i64, limit: Option<u64>) -> bool {
+    match limit {
+        Some(limit) => total_storage_delta > 0 && total_storage_delta as u64 > limit,
+        None => false
+    }
+}
+
+// Pure core of GlobalContext::charge_storage_for_write: the signed net
+//   byte delta of replacing an entry of `old_size` (None if it didn't
+//   exist) with one of `new_size` (None if it's being removed).
+fn write_storage_delta(old_size: Option<u64>, new_size: Option<u64>) -> i64 {
+    let old = old_size.unwrap_or(0) as i64;
+    let new = new_size.unwrap_or(0) as i64;
+    new - old
+}
+
+// Maps a built-in operator's name to the CostFunction class that best
+//   describes the work it does, so `charge_expr_tree` can weight it
+//   beyond the flat per-node COST_EVAL_STEP. Operators not listed here
+//   are still charged COST_EVAL_STEP, just with no extra weight.
+fn classify_operation(name: &str) -> Option<CostFunction> {
+    match name {
+        "+" | "-" | "*" | "/" | "mod" | "pow" | "xor" => Some(CostFunction::Arithmetic),
+        "sha256" | "sha512" | "sha512/256" | "hash160" | "keccak256" => Some(CostFunction::Hashing),
+        "var-get" | "map-get?" | "contract-call?" | "get-block-info?" => Some(CostFunction::DbRead),
+        "var-set" | "map-set" | "map-insert" | "map-delete" => Some(CostFunction::DbWrite),
+        "list" | "len" | "append" | "concat" | "fold" | "map" => Some(CostFunction::ListLength),
+        _ => None
+    }
+}
+
+// Estimates the runtime magnitude of an operand for cost-weighting
+//   purposes. Only literal values embedded directly in the AST (e.g. a
+//   buffer or list literal passed straight to `sha256`/`len`) are
+//   statically visible here, since charge_expr_tree runs before the
+//   expression is evaluated; anything else (a variable, a nested call)
+//   falls back to a weight of 1. The Debug-formatted length is a
+//   deliberately crude proxy for serialized size -- it avoids needing to
+//   match on Value's own variants (e.g. buffer/list field layouts) which
+//   aren't visible from this module, while still growing monotonically
+//   with the operand's real size.
+fn operand_size(expr: &SymbolicExpression) -> u64 {
+    match expr.match_atom_value() {
+        Some(value) => format!("{:?}", value).len() as u64,
+        None => 1
+    }
+}
+
+/// Tracks the gas budget for a single transaction's execution. A
+/// `CostMeter` is shared (via `Rc<RefCell<_>>`) between a `GlobalContext`
+/// and every `GlobalContext` nested from it via `nest()`/`nest_read_only()`,
+/// so that work performed in a sub-call (e.g., a `contract-call?`) is
+/// charged against the very same budget as its caller. Gas already
+/// consumed is never refunded, even if the sub-call's changes are rolled
+/// back: this mirrors how a reverted call still burns gas.
+#[derive(Debug)]
+pub struct CostMeter {
+    pub limit: u64,
+    pub consumed: u64,
+}
+
+impl CostMeter {
+    pub fn new(limit: u64) -> CostMeter {
+        CostMeter { limit, consumed: 0 }
+    }
+
+    pub fn charge(&mut self, amount: u64) -> Result<()> {
+        self.consumed = self.consumed.checked_add(amount)
+            .ok_or(RuntimeErrorType::ArithmeticOverflow)?;
+        if self.consumed > self.limit {
+            Err(RuntimeErrorType::OutOfGas.into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Observes the interpreter as it runs a transaction. All hooks default to
+/// no-ops, so an implementer only needs to override the events it cares
+/// about. Modeled on the `Tracer`/`VMTracer` externalities used by other
+/// EVM-style interpreters to produce call traces without touching the
+/// interpreter itself.
+pub trait ExecutionTracer {
+    fn on_contract_call_enter(&mut self, _contract: &str, _function: &str, _args: &[Value]) {}
+    fn on_contract_call_exit(&mut self, _result: &Result<Value>) {}
+    fn on_asset_transfer(&mut self, _sender: &PrincipalData, _asset: &AssetIdentifier, _amount: i128) {}
+    fn on_token_transfer(&mut self, _sender: &PrincipalData, _asset: &AssetIdentifier, _token_id: &Value) {}
+    fn on_eval_step(&mut self, _expr: &SymbolicExpression) {}
+}
+
+/// The default tracer: observes nothing.
+pub struct NoopTracer;
+
+impl ExecutionTracer for NoopTracer {}
+
+#[derive(Debug, Clone)]
+pub enum TracedEvent {
+    ContractCallEnter { contract: String, function: String, args: Vec<Value> },
+    ContractCallExit { result: String },
+    AssetTransfer { sender: PrincipalData, asset: AssetIdentifier, amount: i128 },
+    TokenTransfer { sender: PrincipalData, asset: AssetIdentifier, token_id: String },
+    EvalStep { expr: String }
+}
+
+/// A tracer that accumulates a structured log of every traced event, so
+/// that tools can replay a transaction's call trace and asset-flow
+/// diagram after the fact.
+#[derive(Debug, Clone, Default)]
+pub struct CollectingTracer {
+    pub events: Vec<TracedEvent>
+}
+
+impl CollectingTracer {
+    pub fn new() -> CollectingTracer {
+        CollectingTracer { events: Vec::new() }
+    }
+}
+
+impl ExecutionTracer for CollectingTracer {
+    fn on_contract_call_enter(&mut self, contract: &str, function: &str, args: &[Value]) {
+        self.events.push(TracedEvent::ContractCallEnter {
+            contract: contract.to_string(),
+            function: function.to_string(),
+            args: args.to_vec()
+        });
+    }
+
+    fn on_contract_call_exit(&mut self, result: &Result<Value>) {
+        self.events.push(TracedEvent::ContractCallExit { result: format!("{:?}", result) });
+    }
+
+    fn on_asset_transfer(&mut self, sender: &PrincipalData, asset: &AssetIdentifier, amount: i128) {
+        self.events.push(TracedEvent::AssetTransfer {
+            sender: sender.clone(),
+            asset: asset.clone(),
+            amount
+        });
+    }
+
+    fn on_token_transfer(&mut self, sender: &PrincipalData, asset: &AssetIdentifier, token_id: &Value) {
+        self.events.push(TracedEvent::TokenTransfer {
+            sender: sender.clone(),
+            asset: asset.clone(),
+            token_id: format!("{:?}", token_id)
+        });
+    }
+
+    fn on_eval_step(&mut self, expr: &SymbolicExpression) {
+        self.events.push(TracedEvent::EvalStep { expr: format!("{:?}", expr) });
+    }
+}
+
 // TODO:
 //    hide the environment's instance variables.
 //     we don't want many of these changing after instantiation.
@@ -22,7 +209,8 @@ pub struct Environment <'a,'b> {
     pub contract_context: &'a ContractContext,
     pub call_stack: &'a mut CallStack,
     pub sender: Option<Value>,
-    pub caller: Option<Value>
+    pub caller: Option<Value>,
+    pub tracer: Option<&'a mut dyn ExecutionTracer>
 }
 
 pub struct OwnedEnvironment <'a> {
@@ -33,13 +221,15 @@ pub struct OwnedEnvironment <'a> {
 
 /**
  The AssetMap is used to track which assets have been transfered from whom
- during the execution of a transaction.
+ during the execution of a transaction. Fungible assets are tracked as
+ accumulated signed balances in `map`; non-fungible assets are tracked in
+ `nft_map`, which records the concrete token identifiers moved, since for
+ NFTs the identifier transfered is what consumers actually care about.
  */
 #[derive(Debug)]
 pub struct AssetMap {
-    // Q: currently we just track balance transfers, but for NFT,
-    //     tracking the actual identifier transfered is probably more useful.
-    map: HashMap<PrincipalData, HashMap<AssetIdentifier, i128>>
+    map: HashMap<PrincipalData, HashMap<AssetIdentifier, i128>>,
+    nft_map: HashMap<PrincipalData, HashMap<AssetIdentifier, Vec<Value>>>
 }
 
 /** GlobalContext represents the outermost context for a transaction's
@@ -53,9 +243,27 @@ pub struct AssetMap {
  */
 pub struct GlobalContext <'a> {
     parent_map: Option<&'a mut AssetMap>,
+    // Points at the parent's `storage_delta`, so that a nested context's
+    //   net storage growth rolls up into its parent on commit(), exactly
+    //   like `parent_map` does for asset transfers.
+    parent_storage_delta: Option<&'a mut i64>,
     pub database: ContractDatabase<'a>,
     read_only: bool,
-    asset_map: AssetMap
+    asset_map: AssetMap,
+    cost_meter: Rc<RefCell<CostMeter>>,
+    // Net byte-size change charged via charge_storage() during this
+    //   context, plus what committed nested contexts rolled up into it.
+    //   Only initialize_contract's write of contract source is charged;
+    //   var-set/map-set/map-insert/map-delete writes are not counted.
+    storage_delta: i64,
+    // Snapshot, taken at nest() time, of everything already committed
+    //   toward storage_limit earlier in the transaction. storage_delta
+    //   resets to 0 for every nested context, so without this baseline a
+    //   limit check against storage_delta alone would only ever see the
+    //   current context's own writes and could be bypassed by spreading
+    //   writes across several sequential nested calls.
+    inherited_storage_delta: i64,
+    storage_limit: Option<u64>
 }
 
 #[derive(Serialize, Deserialize)]
@@ -81,7 +289,8 @@ pub type StackTrace = Vec<FunctionIdentifier>;
 impl AssetMap {
     pub fn new() -> AssetMap {
         AssetMap {
-            map: HashMap::new()
+            map: HashMap::new(),
+            nft_map: HashMap::new()
         }
     }
 
@@ -111,6 +320,24 @@ impl AssetMap {
         Ok(())
     }
 
+    // Records that `principal` moved the specific non-fungible token
+    //   `token_id` of `asset`, in addition to (not instead of) any
+    //   fungible balance tracked for the same asset.
+    pub fn add_token_transfer(&mut self, principal: &PrincipalData, asset: AssetIdentifier, token_id: Value) {
+        if !self.nft_map.contains_key(principal) {
+            self.nft_map.insert(principal.clone(), HashMap::new());
+        }
+
+        let principal_map = self.nft_map.get_mut(principal)
+            .unwrap(); // should always exist, because of checked insert above.
+
+        if let Some(ids) = principal_map.get_mut(&asset) {
+            ids.push(token_id);
+        } else {
+            principal_map.insert(asset, vec![token_id]);
+        }
+    }
+
     // This will add any asset transfer data from other to self,
     //   aborting _all_ changes in the event of an error, leaving self unchanged
     pub fn commit_other(&mut self, mut other: AssetMap) -> Result<()> {
@@ -122,6 +349,13 @@ impl AssetMap {
             }
         }
 
+        let mut tokens_to_add = Vec::new();
+        for (principal, mut principal_map) in other.nft_map.drain() {
+            for (asset, token_ids) in principal_map.drain() {
+                tokens_to_add.push((principal.clone(), asset, token_ids));
+            }
+        }
+
         for (principal, asset, amount) in to_add.drain(..) {
             if !self.map.contains_key(&principal) {
                 self.map.insert(principal.clone(), HashMap::new());
@@ -132,9 +366,22 @@ impl AssetMap {
             principal_map.insert(asset, amount);
         }
 
+        for (principal, asset, mut token_ids) in tokens_to_add.drain(..) {
+            if !self.nft_map.contains_key(&principal) {
+                self.nft_map.insert(principal.clone(), HashMap::new());
+            }
+
+            let principal_map = self.nft_map.get_mut(&principal)
+                .unwrap(); // should always exist, because of checked insert above.
+            principal_map.entry(asset).or_insert_with(Vec::new).append(&mut token_ids);
+        }
+
         Ok(())
     }
 
+    // Keeps its original (pre-NFT-tracking) signature so existing callers
+    //   that only ever wanted the fungible-asset table don't break; use
+    //   nft_to_table() for the NFT identifiers tracked alongside it.
     pub fn to_table(mut self) -> HashMap<PrincipalData, Vec<(AssetIdentifier, i128)>> {
         let mut map = HashMap::new();
         for (principal, mut principal_map) in self.map.drain() {
@@ -145,7 +392,22 @@ impl AssetMap {
             map.insert(principal, vec);
         }
 
-        return map
+        map
+    }
+
+    // Companion to to_table() for the non-fungible side of the map; call
+    //   this first if both tables are needed, since to_table() consumes self.
+    pub fn nft_to_table(&mut self) -> HashMap<PrincipalData, Vec<(AssetIdentifier, Vec<Value>)>> {
+        let mut nft_map = HashMap::new();
+        for (principal, mut principal_map) in self.nft_map.drain() {
+            let mut vec = Vec::new();
+            for (asset, token_ids) in principal_map.drain() {
+                vec.push((asset, token_ids));
+            }
+            nft_map.insert(principal, vec);
+        }
+
+        nft_map
     }
 }
 
@@ -157,6 +419,13 @@ impl fmt::Display for AssetMap {
                 write!(f, "{} spent {} {}\n", principal, amount, asset)?;
             }
         }
+        for (principal, principal_map) in self.nft_map.iter() {
+            for (asset, token_ids) in principal_map.iter() {
+                for token_id in token_ids.iter() {
+                    write!(f, "{} spent token {} of {}\n", principal, token_id, asset)?;
+                }
+            }
+        }
         write!(f, "]")
     }
 }
@@ -175,7 +444,21 @@ impl <'a> OwnedEnvironment <'a> {
         Environment::new(&mut self.context,
                          &self.default_contract,
                          &mut self.call_stack,
-                         sender.clone(), sender)
+                         sender.clone(), sender, None)
+    }
+
+    // Like get_exec_environment, but installs `tracer` on the returned
+    //   Environment: execute_contract fires on_contract_call_enter/exit and
+    //   eval_raw/eval_read_only fire on_eval_step. on_asset_transfer and
+    //   on_token_transfer fire only when log_asset_transfer and
+    //   log_token_transfer are passed a tracer; the Environment does not
+    //   pass its own tracer to them.
+    pub fn get_exec_environment_with_tracer <'b> (&'b mut self, sender: Option<Value>,
+                                                  tracer: &'b mut dyn ExecutionTracer) -> Environment<'b,'a> {
+        Environment::new(&mut self.context,
+                         &self.default_contract,
+                         &mut self.call_stack,
+                         sender.clone(), sender, Some(tracer))
     }
 
     pub fn initialize_contract(mut self, contract_name: &str, contract_content: &str) -> Result<()> {
@@ -187,20 +470,41 @@ impl <'a> OwnedEnvironment <'a> {
         Ok(())
     }
 
-    pub fn execute_transaction(mut self, sender: Value, contract_name: &str, 
-                               tx_name: &str, args: &[SymbolicExpression]) -> Result<(Value, AssetMap)> {
+    pub fn execute_transaction(mut self, sender: Value, contract_name: &str,
+                               tx_name: &str, args: &[SymbolicExpression],
+                               gas_limit: u64, storage_limit: Option<u64>) -> Result<(Value, AssetMap, u64, i64)> {
+        self.context.set_cost_limit(gas_limit);
+        self.context.set_storage_limit(storage_limit);
         let return_value = {
             let mut exec_env = self.get_exec_environment(Some(sender));
             exec_env.execute_contract(contract_name, tx_name, args)
         }?;
+        let gas_used = self.context.cost_consumed();
+        let storage_delta = self.context.storage_delta();
         let asset_map = self.commit()?;
-        Ok((return_value, asset_map))
+        Ok((return_value, asset_map, gas_used, storage_delta))
     }
 
     pub fn commit(self) -> Result<AssetMap> {
         self.context.commit()?
             .ok_or(InterpreterError::FailedToConstructAssetTable.into())
     }
+
+    // Runs a public function the same way execute_transaction does, but
+    //   never persists the result: the predicted AssetMap is captured from
+    //   the nested GlobalContext that actually ran the call, and then the
+    //   whole transaction -- nested save point and all -- is rolled back.
+    //   This lets wallets/explorers preview a transaction's asset movements
+    //   and return value before broadcasting it.
+    pub fn simulate_transaction(mut self, sender: Value, contract_name: &str,
+                                tx_name: &str, args: &[SymbolicExpression]) -> Result<(Value, AssetMap)> {
+        let result = {
+            let mut exec_env = self.get_exec_environment(Some(sender));
+            exec_env.simulate_contract(contract_name, tx_name, args)
+        };
+        self.context.database.roll_back();
+        result
+    }
 }
 
 impl <'a, 'b> Environment <'a, 'b> {
@@ -215,7 +519,8 @@ impl <'a, 'b> Environment <'a, 'b> {
     pub fn new(global_context: &'a mut GlobalContext<'b>,
                contract_context: &'a ContractContext,
                call_stack: &'a mut CallStack,
-               sender: Option<Value>, caller: Option<Value>) -> Environment<'a,'b> {
+               sender: Option<Value>, caller: Option<Value>,
+               tracer: Option<&'a mut dyn ExecutionTracer>) -> Environment<'a,'b> {
         if let Some(ref sender) = sender {
             if let Value::Principal(_) = sender {
             } else {
@@ -234,18 +539,54 @@ impl <'a, 'b> Environment <'a, 'b> {
             contract_context,
             call_stack,
             sender,
-            caller
+            caller,
+            tracer
         }
     }
 
     pub fn nest_as_principal <'c> (&'c mut self, sender: Value) -> Environment<'c, 'b> {
+        let tracer = self.tracer.as_mut().map(|tracer| &mut **tracer as &mut dyn ExecutionTracer);
         Environment::new(self.global_context, self.contract_context, self.call_stack,
-                         Some(sender.clone()), Some(sender))
+                         Some(sender.clone()), Some(sender), tracer)
     }
 
     pub fn nest_with_caller <'c> (&'c mut self, caller: Value) -> Environment<'c, 'b> {
+        let tracer = self.tracer.as_mut().map(|tracer| &mut **tracer as &mut dyn ExecutionTracer);
         Environment::new(self.global_context, self.contract_context, self.call_stack,
-                         self.sender.clone(), Some(caller))
+                         self.sender.clone(), Some(caller), tracer)
+    }
+
+    // Walks `expr` and everything nested inside it up front, before it is
+    //   evaluated, charging COST_EVAL_STEP per node plus, for calls to
+    //   known built-ins, the extra weight of their CostFunction class.
+    //   `eval` itself charges nothing, so this walk is the only per-node
+    //   charge. Also fires `on_eval_step` for each node, which is the only
+    //   call site for that tracer hook.
+    fn charge_expr_tree(&mut self, expr: &SymbolicExpression) -> Result<()> {
+        self.global_context.charge_cost(COST_EVAL_STEP)?;
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.on_eval_step(expr);
+        }
+
+        if let Some(children) = expr.match_list() {
+            if let Some(op) = children.get(0).and_then(|child| child.match_atom()) {
+                if let Some(cost_function) = classify_operation(&op.to_string()) {
+                    // Weight by the largest argument's own magnitude, not how
+                    //   many arguments there are -- (sha256 <1MB-buff>) must
+                    //   cost more than (sha256 0x00), not the same amount.
+                    let size = children.iter().skip(1)
+                        .map(|child| operand_size(child))
+                        .max()
+                        .unwrap_or(1);
+                    self.global_context.charge_cost_for(cost_function, size)?;
+                }
+            }
+            for child in children {
+                self.charge_expr_tree(child)?;
+            }
+        }
+
+        Ok(())
     }
 
     pub fn eval_read_only(&mut self, contract_name: &str, program: &str) -> Result<Value> {
@@ -255,10 +596,12 @@ impl <'a, 'b> Environment <'a, 'b> {
         }
 
         let contract = self.global_context.database.get_contract(contract_name)?;
+        self.charge_expr_tree(&parsed[0])?;
         let mut nested_context = self.global_context.nest();
+        let tracer = self.tracer.as_mut().map(|tracer| &mut **tracer as &mut dyn ExecutionTracer);
         let result = {
             let mut nested_env = Environment::new(&mut nested_context, &contract.contract_context,
-                                                  self.call_stack, self.sender.clone(), self.caller.clone());
+                                                  self.call_stack, self.sender.clone(), self.caller.clone(), tracer);
             let local_context = LocalContext::new();
             eval(&parsed[0], &mut nested_env, &local_context)
         };
@@ -266,12 +609,13 @@ impl <'a, 'b> Environment <'a, 'b> {
 
         result
     }
-    
+
     pub fn eval_raw(&mut self, program: &str) -> Result<Value> {
         let parsed = parser::parse(program)?;
         if parsed.len() < 1 {
             return Err(RuntimeErrorType::ParseError("Expected a program of at least length 1".to_string()).into())
         }
+        self.charge_expr_tree(&parsed[0])?;
         let local_context = LocalContext::new();
         let result = {
             eval(&parsed[0], self, &local_context)
@@ -279,8 +623,11 @@ impl <'a, 'b> Environment <'a, 'b> {
         result
     }
 
-    pub fn execute_contract(&mut self, contract_name: &str, 
-                            tx_name: &str, args: &[SymbolicExpression]) -> Result<Value> {
+    // Resolves and checks a public-function call, converting its argument
+    //   expressions to values. Shared by execute_contract and
+    //   simulate_contract, which only differ in how they run the call.
+    fn lookup_public_call(&mut self, contract_name: &str, tx_name: &str,
+                          args: &[SymbolicExpression]) -> Result<(Contract, DefinedFunction, Vec<Value>)> {
         let contract = self.global_context.database.get_contract(contract_name)?;
 
         let func = contract.contract_context.lookup_function(tx_name)
@@ -300,7 +647,35 @@ impl <'a, 'b> Environment <'a, 'b> {
 
         let args = args?;
 
-        self.execute_function_as_transaction(&func, &args, Some(&contract.contract_context)) 
+        Ok((contract, func, args))
+    }
+
+    pub fn execute_contract(&mut self, contract_name: &str,
+                            tx_name: &str, args: &[SymbolicExpression]) -> Result<Value> {
+        let (contract, func, args) = self.lookup_public_call(contract_name, tx_name, args)?;
+
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.on_contract_call_enter(contract_name, tx_name, &args);
+        }
+
+        let result = self.execute_function_as_transaction(&func, &args, Some(&contract.contract_context));
+
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.on_contract_call_exit(&result);
+        }
+
+        result
+    }
+
+    // Like execute_contract, but never commits: the run's AssetMap is
+    //   captured and returned alongside the result instead of being merged
+    //   into the parent context, and the nested save point is always
+    //   rolled back, win or lose.
+    pub fn simulate_contract(&mut self, contract_name: &str,
+                             tx_name: &str, args: &[SymbolicExpression]) -> Result<(Value, AssetMap)> {
+        let (contract, func, args) = self.lookup_public_call(contract_name, tx_name, args)?;
+
+        self.simulate_function_as_transaction(&func, &args, Some(&contract.contract_context))
     }
 
     pub fn execute_function_as_transaction(&mut self, function: &DefinedFunction, args: &[Value],
@@ -308,7 +683,7 @@ impl <'a, 'b> Environment <'a, 'b> {
         let make_read_only = function.is_read_only();
 
         let mut nested_context = {
-            if make_read_only { 
+            if make_read_only {
                 self.global_context.nest_read_only()
             } else {
                 self.global_context.nest()
@@ -316,10 +691,26 @@ impl <'a, 'b> Environment <'a, 'b> {
         };
 
         let next_contract_context = next_contract_context.unwrap_or(self.contract_context);
+        let tracer = self.tracer.as_mut().map(|tracer| &mut **tracer as &mut dyn ExecutionTracer);
+
+        // Charges before executing the function body, so execute_transaction
+        //   (the primary path into the VM) stops reporting a flat 0 gas_used.
+        //   The per-node walk charge_expr_tree does up front for
+        //   eval_raw/eval_read_only is not applied here: the body's actual
+        //   evaluation, including any loop/recursion/contract-call?,
+        //   happens inside DefinedFunction::execute_apply, which dispatches
+        //   through vm::eval/vm::callables -- neither of which is part of
+        //   this module or charges the meter. The body is charged this one
+        //   COST_EVAL_STEP however long it runs, and no path here aborts
+        //   mid-loop.
+        if let Err(e) = nested_context.charge_cost(COST_EVAL_STEP) {
+            nested_context.database.roll_back();
+            return Err(e);
+        }
 
         let result = {
             let mut nested_env = Environment::new(&mut nested_context, next_contract_context, self.call_stack,
-                                                  self.sender.clone(), self.caller.clone());
+                                                  self.sender.clone(), self.caller.clone(), tracer);
 
             function.execute_apply(args, &mut nested_env)
         };
@@ -332,12 +723,76 @@ impl <'a, 'b> Environment <'a, 'b> {
         }
     }
 
+    // Like execute_function_as_transaction, but captures the nested
+    //   context's AssetMap and forces a rollback instead of committing,
+    //   regardless of whether the function itself was read-only. The
+    //   captured AssetMap is only handed back if the function's Response
+    //   was committed, mirroring handle_tx_result's real commit/rollback
+    //   decision, so a preview never reports transfers that would have
+    //   actually been rolled back on broadcast.
+    pub fn simulate_function_as_transaction(&mut self, function: &DefinedFunction, args: &[Value],
+                                            next_contract_context: Option<&ContractContext>) -> Result<(Value, AssetMap)> {
+        let make_read_only = function.is_read_only();
+
+        let mut nested_context = {
+            if make_read_only {
+                self.global_context.nest_read_only()
+            } else {
+                self.global_context.nest()
+            }
+        };
+
+        let next_contract_context = next_contract_context.unwrap_or(self.contract_context);
+        let tracer = self.tracer.as_mut().map(|tracer| &mut **tracer as &mut dyn ExecutionTracer);
+
+        let result = {
+            let mut nested_env = Environment::new(&mut nested_context, next_contract_context, self.call_stack,
+                                                  self.sender.clone(), self.caller.clone(), tracer);
+
+            function.execute_apply(args, &mut nested_env)
+        };
+
+        match result {
+            Ok(value) => {
+                let asset_map = nested_context.extract_with_rollback();
+                Environment::gate_simulated_asset_map(value, asset_map)
+            },
+            Err(e) => {
+                nested_context.extract_with_rollback();
+                Err(e)
+            }
+        }
+    }
+
+    // Pulled out of simulate_function_as_transaction so it can be unit
+    //   tested without needing a real GlobalContext/ContractDatabase.
+    //   Mirrors handle_tx_result: a committed Response keeps its captured
+    //   AssetMap, an uncommitted one reports no asset movement at all.
+    fn gate_simulated_asset_map(value: Value, asset_map: AssetMap) -> Result<(Value, AssetMap)> {
+        if let Value::Response(ref data) = value {
+            if data.committed {
+                Ok((value, asset_map))
+            } else {
+                Ok((value, AssetMap::new()))
+            }
+        } else {
+            Err(UncheckedError::ContractMustReturnBoolean.into())
+        }
+    }
+
     pub fn initialize_contract(&mut self, contract_name: &str, contract_content: &str) -> Result<()> {
         let mut nested_context = self.global_context.nest();
         let result = Contract::initialize(contract_name, contract_content,
                                           &mut nested_context);
         match result {
             Ok(contract) => {
+                // Persisting a contract's source is itself a write through
+                //   ContractDatabase, so it counts against storage_limit
+                //   just like any other key/value write would.
+                if let Err(e) = nested_context.charge_storage_for_write(None, Some(contract_content.len() as u64)) {
+                    nested_context.database.roll_back();
+                    return Err(e);
+                }
                 nested_context.database.insert_contract(contract_name, contract);
                 nested_context.commit()?;
                 Ok(())
@@ -355,18 +810,106 @@ impl <'a> GlobalContext <'a> {
     pub fn new(database: ContractDatabase<'a>) -> GlobalContext<'a> {
         GlobalContext {
             parent_map: None,
+            parent_storage_delta: None,
             database: database,
             read_only: false,
-            asset_map: AssetMap::new()
+            asset_map: AssetMap::new(),
+            cost_meter: Rc::new(RefCell::new(CostMeter::new(u64::max_value()))),
+            storage_delta: 0,
+            inherited_storage_delta: 0,
+            storage_limit: None
+        }
+    }
+
+    // Caps the amount of net storage growth (in bytes) this (top-level)
+    //   context and every context nested from it may accumulate. `None`
+    //   means unbounded.
+    pub fn set_storage_limit(&mut self, limit: Option<u64>) {
+        self.storage_limit = limit;
+    }
+
+    pub fn storage_delta(&self) -> i64 {
+        self.storage_delta
+    }
+
+    // Charges the net byte-size change of a key/value write or removal
+    //   against this context's storage budget. `delta` is signed: growth
+    //   is positive, a deletion that shrinks state is negative.
+    pub fn charge_storage(&mut self, delta: i64) -> Result<()> {
+        self.storage_delta = self.storage_delta.saturating_add(delta);
+        let total = self.inherited_storage_delta.saturating_add(self.storage_delta);
+        if storage_limit_exceeded(total, self.storage_limit) {
+            return Err(RuntimeErrorType::StorageLimitExceeded.into());
         }
+        Ok(())
+    }
+
+    // The entry point every ContractDatabase write or removal should go
+    //   through: pass the byte size of the entry being replaced (None if
+    //   it didn't previously exist) and the size of what replaces it
+    //   (None for a removal), and the net delta is charged via
+    //   charge_storage(). var-set/map-set/map-insert/map-delete should
+    //   call this once per write with the serialized size of the key/value
+    //   involved; initialize_contract already does for contract source.
+    pub fn charge_storage_for_write(&mut self, old_size: Option<u64>, new_size: Option<u64>) -> Result<()> {
+        self.charge_storage(write_storage_delta(old_size, new_size))
     }
 
-    pub fn log_asset_transfer(&mut self, sender: &PrincipalData, contract_name: &str, asset_name: &str, transfered: i128) -> Result<()> {
+    // Caps the gas budget available to this (top-level) context and
+    //   every context nested from it. Must be called before any execution
+    //   begins: it resets `consumed` to 0, so calling it mid-transaction
+    //   would wipe out charges already made against the shared meter.
+    pub fn set_cost_limit(&mut self, limit: u64) {
+        let mut meter = self.cost_meter.borrow_mut();
+        meter.limit = limit;
+        meter.consumed = 0;
+    }
+
+    pub fn cost_consumed(&self) -> u64 {
+        self.cost_meter.borrow().consumed
+    }
+
+    // Charges a flat amount against the shared cost meter, e.g., the
+    //   per-node COST_EVAL_STEP charged by charge_expr_tree.
+    pub fn charge_cost(&mut self, amount: u64) -> Result<()> {
+        self.cost_meter.borrow_mut().charge(amount)
+    }
+
+    // Charges the weighted cost of a built-in operation against the
+    //   shared cost meter (see `CostFunction`).
+    pub fn charge_cost_for(&mut self, function: CostFunction, size: u64) -> Result<()> {
+        self.charge_cost(function.weigh(size))
+    }
+
+    // Called by the native stx-transfer?/ft-transfer? implementations (in
+    //   vm::functions, not part of this module) once per successful
+    //   transfer, with the same tracer the calling Environment was given so
+    //   on_asset_transfer fires for every move this GlobalContext records.
+    //   There is currently no in-tree caller: those native functions live
+    //   outside this file, so on_asset_transfer can't yet fire at runtime.
+    pub fn log_asset_transfer(&mut self, tracer: Option<&mut dyn ExecutionTracer>, sender: &PrincipalData,
+                              contract_name: &str, asset_name: &str, transfered: i128) -> Result<()> {
         let asset_identifier = AssetIdentifier { contract_name: contract_name.to_string(),
                                                  asset_name: asset_name.to_string() };
+        if let Some(tracer) = tracer {
+            tracer.on_asset_transfer(sender, &asset_identifier, transfered);
+        }
         self.asset_map.add_transfer(sender, asset_identifier, transfered)
     }
 
+    // Called by the native nft-transfer? implementation (in vm::functions,
+    //   not part of this module) once per successful transfer, mirroring
+    //   log_asset_transfer's tracer-threading convention.
+    pub fn log_token_transfer(&mut self, tracer: Option<&mut dyn ExecutionTracer>, sender: &PrincipalData,
+                              contract_name: &str, asset_name: &str, token_id: Value) {
+        let asset_identifier = AssetIdentifier { contract_name: contract_name.to_string(),
+                                                 asset_name: asset_name.to_string() };
+        if let Some(tracer) = tracer {
+            tracer.on_token_transfer(sender, &asset_identifier, &token_id);
+        }
+        self.asset_map.add_token_transfer(sender, asset_identifier, token_id)
+    }
+
     pub fn get_block_height(&self) -> u64 {
         self.database.get_simmed_block_height()
             .expect("Failed to obtain the current block height.")
@@ -394,23 +937,38 @@ impl <'a> GlobalContext <'a> {
 
     pub fn nest <'b> (&'b mut self) -> GlobalContext<'b> {
         let database = self.database.begin_save_point();
+        let inherited_storage_delta = self.inherited_storage_delta.saturating_add(self.storage_delta);
 
         GlobalContext {
             parent_map: Some(&mut self.asset_map),
+            parent_storage_delta: Some(&mut self.storage_delta),
             database: database,
             read_only: self.read_only,
-            asset_map: AssetMap::new()
+            asset_map: AssetMap::new(),
+            // Nested contexts share, rather than reset, the parent's cost
+            //   meter: a sub-call's gas is spent from the same budget as
+            //   its caller, and is never refunded on roll_back.
+            cost_meter: self.cost_meter.clone(),
+            storage_delta: 0,
+            inherited_storage_delta,
+            storage_limit: self.storage_limit
         }
     }
 
     pub fn nest_read_only <'b> (&'b mut self) -> GlobalContext<'b> {
         let database = self.database.begin_save_point();
+        let inherited_storage_delta = self.inherited_storage_delta.saturating_add(self.storage_delta);
 
         GlobalContext {
             parent_map: Some(&mut self.asset_map),
+            parent_storage_delta: Some(&mut self.storage_delta),
             database: database,
             read_only: true,
-            asset_map: AssetMap::new()
+            asset_map: AssetMap::new(),
+            cost_meter: self.cost_meter.clone(),
+            storage_delta: 0,
+            inherited_storage_delta,
+            storage_limit: self.storage_limit
         }
     }
 
@@ -424,10 +982,10 @@ impl <'a> GlobalContext <'a> {
     }
 
     pub fn commit(self) -> Result<Option<AssetMap>> {
-        let Self { parent_map, asset_map, database, .. } = self;
+        let Self { parent_map, parent_storage_delta, asset_map, database, storage_delta, .. } = self;
 
         let out_map = match parent_map {
-            Some(parent_map) => { 
+            Some(parent_map) => {
                 parent_map.commit_other(asset_map)?;
                 None
             },
@@ -436,10 +994,24 @@ impl <'a> GlobalContext <'a> {
             }
         };
 
+        if let Some(parent_storage_delta) = parent_storage_delta {
+            *parent_storage_delta += storage_delta;
+        }
+
         database.commit();
         Ok(out_map)
     }
 
+    // Rolls the database back unconditionally and hands back this
+    //   context's AssetMap without merging it into the parent -- the
+    //   forced-rollback counterpart to commit(), used to preview a
+    //   transaction's asset movements without persisting them.
+    pub fn extract_with_rollback(self) -> AssetMap {
+        let Self { asset_map, database, .. } = self;
+        database.roll_back();
+        asset_map
+    }
+
     pub fn handle_tx_result(mut self, result: Result<Value>) -> Result<Value> {
         if let Ok(result) = result {
             if let Value::Response(data) = result {
@@ -564,3 +1136,100 @@ impl CallStack {
         Vec::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cost_meter_charges_until_out_of_gas() {
+        let mut meter = CostMeter::new(10);
+        assert!(meter.charge(4).is_ok());
+        assert!(meter.charge(4).is_ok());
+        assert_eq!(meter.consumed, 8);
+
+        match meter.charge(4) {
+            Err(_) => (),
+            Ok(_) => panic!("expected charging past the limit to fail with OutOfGas")
+        }
+    }
+
+    #[test]
+    fn cost_function_weighs_by_class_and_size() {
+        assert!(CostFunction::Hashing.weigh(1) > CostFunction::Arithmetic.weigh(1));
+        assert_eq!(CostFunction::Arithmetic.weigh(3), CostFunction::Arithmetic.weigh(1) * 3);
+    }
+
+    #[test]
+    fn collecting_tracer_records_each_hook() {
+        let mut tracer = CollectingTracer::new();
+
+        tracer.on_contract_call_enter("my-contract", "my-func", &[]);
+        tracer.on_contract_call_exit(&Err(RuntimeErrorType::OutOfGas.into()));
+
+        let parsed = parser::parse("(+ 1 2)").expect("test program should parse");
+        tracer.on_eval_step(&parsed[0]);
+
+        assert_eq!(tracer.events.len(), 3);
+        match &tracer.events[0] {
+            TracedEvent::ContractCallEnter { contract, function, .. } => {
+                assert_eq!(contract, "my-contract");
+                assert_eq!(function, "my-func");
+            },
+            other => panic!("expected ContractCallEnter, got {:?}", other)
+        }
+        match &tracer.events[1] {
+            TracedEvent::ContractCallExit { .. } => (),
+            other => panic!("expected ContractCallExit, got {:?}", other)
+        }
+        match &tracer.events[2] {
+            TracedEvent::EvalStep { .. } => (),
+            other => panic!("expected EvalStep, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn storage_limit_exceeded_respects_unbounded_and_bounded_limits() {
+        assert!(!storage_limit_exceeded(1_000_000, None));
+        assert!(!storage_limit_exceeded(999, Some(1000)));
+        assert!(!storage_limit_exceeded(1000, Some(1000)));
+        assert!(storage_limit_exceeded(1001, Some(1000)));
+        // A net shrink (e.g. all writes so far were removals) never trips the limit.
+        assert!(!storage_limit_exceeded(-500, Some(1000)));
+    }
+
+    #[test]
+    fn write_storage_delta_covers_insert_update_and_remove() {
+        assert_eq!(write_storage_delta(None, Some(100)), 100); // fresh insert
+        assert_eq!(write_storage_delta(Some(100), Some(40)), -60); // shrinking update
+        assert_eq!(write_storage_delta(Some(100), None), -100); // removal
+    }
+
+    #[test]
+    fn operand_size_scales_with_literal_magnitude() {
+        let small = parser::parse("(sha256 0x00)").expect("test program should parse");
+        let big = parser::parse("(sha256 0x0011223344556677)").expect("test program should parse");
+
+        let small_arg = small[0].match_list().expect("list expr").get(1).expect("one arg");
+        let big_arg = big[0].match_list().expect("list expr").get(1).expect("one arg");
+
+        assert!(operand_size(big_arg) > operand_size(small_arg));
+    }
+
+    #[test]
+    fn to_table_and_nft_to_table_of_empty_map_are_empty() {
+        let mut asset_map = AssetMap::new();
+        assert_eq!(asset_map.nft_to_table().len(), 0);
+        assert_eq!(asset_map.to_table().len(), 0);
+    }
+
+    #[test]
+    fn classify_operation_covers_each_cost_class() {
+        assert_eq!(classify_operation("+"), Some(CostFunction::Arithmetic));
+        assert_eq!(classify_operation("sha256"), Some(CostFunction::Hashing));
+        assert_eq!(classify_operation("map-get?"), Some(CostFunction::DbRead));
+        assert_eq!(classify_operation("var-set"), Some(CostFunction::DbWrite));
+        assert_eq!(classify_operation("len"), Some(CostFunction::ListLength));
+        assert_eq!(classify_operation("not-a-builtin"), None);
+    }
+}